@@ -0,0 +1,103 @@
+// Joseph Prichard
+// Two-phase archive assembly: compress blocks in parallel, then commit them with
+// resolved offsets in a single-threaded pass
+
+use std::io;
+use std::io::Write;
+use rayon::prelude::*;
+use crate::data::FileBlock;
+use crate::write::{BitWriter, BitwiseWriter};
+
+// filename null terminator + tree_bit_size + data_bit_size + crc32 + file_byte_offset + og_byte_size
+const BLOCK_HEADER_FIXED_LEN: u64 = 1 + 8 + 8 + 4 + 8 + 8;
+
+// a block compressed independently in memory; its payload bytes are final but its
+// position in the archive isn't resolved until the commit phase lays out every block
+struct StagedBlock {
+    block: FileBlock,
+    payload: Vec<u8>,
+}
+
+// phase one: compresses every file across the rayon thread pool into its own staged
+// block. If any file fails, `collect` short-circuits and every already-staged buffer
+// produced so far is dropped as part of unwinding the intermediate Vec, so a partial
+// failure never leaves behind a half-written archive.
+fn compress_all(filepaths: &[String]) -> io::Result<Vec<StagedBlock>> {
+    filepaths
+        .par_iter()
+        .map(|filepath| compress_one(filepath))
+        .collect()
+}
+
+fn compress_one(filepath: &str) -> io::Result<StagedBlock> {
+    let mut block_writer = BitWriter::from_writer(Vec::new());
+
+    block_writer.begin_crc();
+    let mut block = crate::compress::compress_file(filepath, &mut block_writer)?;
+    // Huffman-coded data almost never ends on a byte boundary: align_to_byte() folds
+    // the resulting zero-padded partial byte into the CRC before end_crc() reads it,
+    // so the checksum matches the bytes into_inner() is about to persist
+    block_writer.align_to_byte()?;
+    block.crc32 = block_writer.end_crc();
+    // resolved by the commit phase once every block's payload length is known
+    block.file_byte_offset = 0;
+
+    let payload = block_writer.into_inner()?;
+    Ok(StagedBlock { block, payload })
+}
+
+// the number of bytes write_block emits for one block's header entry: write_block
+// writes one byte per char() of filename_rel (not per UTF-8 byte), so this must count
+// the same way or the offset math diverges for any non-ASCII filename
+fn block_header_len(filename_rel: &str) -> u64 {
+    filename_rel.chars().count() as u64 + BLOCK_HEADER_FIXED_LEN
+}
+
+// phase two: patches each block's file_byte_offset to its final position (measured
+// from the start of the header table, the first byte commit writes) and streams the header table followed by the
+// concatenated payloads through `writer`, in the order `filepaths` was given.
+fn commit<W: Write>(writer: &mut BitWriter<W>, mut staged: Vec<StagedBlock>) -> io::Result<()> {
+    let header_len: u64 = staged
+        .iter()
+        .map(|entry| block_header_len(&entry.block.filename_rel))
+        .sum();
+
+    let mut offset = header_len;
+    for entry in &mut staged {
+        entry.block.file_byte_offset = offset;
+        offset += entry.payload.len() as u64;
+    }
+
+    for entry in &staged {
+        writer.write_block(&entry.block)?;
+    }
+    for entry in &staged {
+        for &byte in &entry.payload {
+            writer.write_byte(byte)?;
+        }
+    }
+    Ok(())
+}
+
+// compresses `filepaths` in parallel and commits the resulting blocks into `writer`,
+// aborting (and discarding every staged buffer) without writing anything if any file
+// fails to compress.
+pub fn write_archive<W: Write>(filepaths: &[String], writer: &mut BitWriter<W>) -> io::Result<()> {
+    let staged = compress_all(filepaths)?;
+    commit(writer, staged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_header_len_counts_chars_not_utf8_bytes() {
+        // "café.txt" is 9 UTF-8 bytes but 8 chars; write_block writes one byte per
+        // char, so the offset math must agree with 8, not the UTF-8 byte length
+        let filename = "café.txt";
+        assert_eq!(filename.len(), 9);
+        assert_eq!(filename.chars().count(), 8);
+        assert_eq!(block_header_len(filename), 8 + BLOCK_HEADER_FIXED_LEN);
+    }
+}
@@ -0,0 +1,101 @@
+// Joseph Prichard
+// Incremental CRC-32 (IEEE/zlib) checksum
+
+use std::io;
+
+const POLYNOMIAL: u32 = 0xEDB88320;
+
+fn build_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for byte in 0..256u32 {
+        let mut crc = byte;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+        }
+        table[byte as usize] = crc;
+    }
+    table
+}
+
+// an incremental CRC-32 accumulator: bytes can be fed in as they're produced so the
+// data never needs a second pass just to compute its checksum
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 {
+            table: build_table(),
+            crc: 0xFFFFFFFF,
+        }
+    }
+
+    // resets the running value so the same accumulator can be reused for the next block
+    pub fn reset(&mut self) {
+        self.crc = 0xFFFFFFFF;
+    }
+
+    pub fn update_byte(&mut self, byte: u8) {
+        let index = ((self.crc ^ byte as u32) & 0xFF) as usize;
+        self.crc = self.table[index] ^ (self.crc >> 8);
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.update_byte(byte);
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
+
+// computes the CRC-32 of a whole byte slice in one call, for callers that already have
+// the full buffer in hand (e.g. verifying a block on decompression)
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finalize()
+}
+
+// recomputes the CRC-32 of `data` and compares it against `expected`, failing loudly
+// instead of letting corrupted or truncated data decode into garbage. `data` is a
+// block's compressed payload bytes, the same bytes its stored crc32 was computed over.
+pub fn verify(expected: u32, data: &[u8]) -> io::Result<()> {
+    let actual = crc32(data);
+    if actual != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("CRC-32 mismatch: expected {:#010x}, got {:#010x}", expected, actual),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_standard_check_value() {
+        // the standard CRC-32 (IEEE/zlib) check value for the ASCII string "123456789"
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn verify_accepts_matching_data() {
+        let data = b"some block payload";
+        assert!(verify(crc32(data), data).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_corrupted_data() {
+        let data = b"some block payload";
+        let mut corrupted = data.to_vec();
+        corrupted[0] ^= 0xFF;
+        assert!(verify(crc32(data), &corrupted).is_err());
+    }
+}
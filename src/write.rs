@@ -3,10 +3,12 @@
 // File writer implementing a bit layer
 
 use std::fs::{File, OpenOptions};
-use std::io::{Write};
-use std::{io, mem};
+use std::io::Write;
+use std::mem::ManuallyDrop;
+use std::{io, mem, ptr};
 use crate::bitwise;
 use crate::bitwise::{SymbolCode};
+use crate::crc32::Crc32;
 use crate::data::{FileBlock};
 
 const BUFFER_LEN: usize = 4096;
@@ -20,32 +22,92 @@ pub trait BitwiseWriter {
     fn write_symbol(&mut self, symbol: &SymbolCode) -> io::Result<()>;
     fn write_block(&mut self, block: &FileBlock) -> io::Result<()>;
     fn write_u64(&mut self, num: u64) -> io::Result<()>;
+    fn write_u32(&mut self, num: u32) -> io::Result<()>;
+    // flushes any partial byte and the remaining buffer, returning the final write result
+    // instead of letting it be silently lost (or panicked on) in Drop
+    fn finish(self) -> io::Result<()>;
+    // starts (or restarts) CRC-32 accumulation over the bytes subsequently written
+    fn begin_crc(&mut self);
+    // stops accumulation and returns the CRC-32 of every completed byte written since
+    // begin_crc(); a trailing partial byte is only included if align_to_byte() ran first
+    fn end_crc(&mut self) -> u32;
 }
 
-pub struct FileWriter {
-    // the file stream to write to
-    file: File,
-    // a buffer storing a block to be written to the file
+// a bit-level writer generic over any Write sink, so the Huffman bit-packing logic isn't
+// tied to File and can target an in-memory buffer, a socket, or any other writer
+pub struct BitWriter<W: Write> {
+    // the underlying sink to write to
+    writer: W,
+    // a buffer storing a block to be written to the sink
     buffer: [u8; BUFFER_LEN],
     // the bit position of the last write in the buffer
     bit_position: u32,
+    // running CRC-32 of the bytes written since the last begin_crc(), only accumulated
+    // while `recording_crc` is set so header/string fields aren't folded into a block's checksum
+    crc: Crc32,
+    recording_crc: bool,
 }
 
+// the bit layer as used by the CLI, targeting a file on disk
+pub type FileWriter = BitWriter<File>;
+
 impl FileWriter {
     pub fn new(filepath: &str) -> io::Result<FileWriter> {
-        Ok(FileWriter {
-            file: OpenOptions::new()
-                .write(true)
-                .append(false)
-                .create(true)
-                .open(filepath)?,
+        let file = OpenOptions::new()
+            .write(true)
+            .append(false)
+            .create(true)
+            .open(filepath)?;
+        Ok(BitWriter::from_writer(file))
+    }
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn from_writer(writer: W) -> BitWriter<W> {
+        BitWriter {
+            writer,
             buffer: [0u8; BUFFER_LEN],
             bit_position: 0,
-        })
+            crc: Crc32::new(),
+            recording_crc: false,
+        }
+    }
+
+    // feeds a completed byte into the running CRC if a block is currently being recorded
+    fn crc_byte(&mut self, byte: u8) {
+        if self.recording_crc {
+            self.crc.update_byte(byte);
+        }
+    }
+
+    // flushes the remaining buffer and hands back the underlying writer, e.g. to pull the
+    // finished bytes out of a `BitWriter<Vec<u8>>` staged in memory. Reimplements the
+    // std BufWriter::into_inner trick: Drop means `self.writer` can't be moved out
+    // normally, so bypass it with ManuallyDrop once the pending flush is done.
+    pub fn into_inner(self) -> io::Result<W> {
+        let mut this = ManuallyDrop::new(self);
+        // extract `writer` unconditionally, even on a flush error, so it's always
+        // dropped (closing a File's fd) instead of leaked by skipping past the
+        // ptr::read via `?` on the failure path
+        let result = this.align_to_byte().and_then(|_| this.persist_buffer());
+        let writer = unsafe { ptr::read(&this.writer) };
+        result?;
+        Ok(writer)
     }
 
     fn persist_buffer(&mut self) -> io::Result<()> {
-        self.file.write(&self.buffer[0..((self.bit_position / 8) as usize)])?;
+        // a single write() may report fewer bytes than requested, so loop until the
+        // whole partial buffer is committed (the write_all contract, applied manually
+        // since we're writing a prefix of `buffer`, not the whole slice)
+        let len = (self.bit_position / 8) as usize;
+        let mut written = 0;
+        while written < len {
+            let n = self.writer.write(&self.buffer[written..len])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer"));
+            }
+            written += n;
+        }
         Ok(())
     }
 
@@ -60,8 +122,15 @@ impl FileWriter {
     }
 }
 
-impl BitwiseWriter for FileWriter {
+impl<W: Write> BitwiseWriter for BitWriter<W> {
     fn align_to_byte(&mut self) -> io::Result<()> {
+        // if this rounds up past a partial byte, that byte is now fully written (the
+        // remaining bits stay 0, since write_bit only ever sets bits, never clears them)
+        // and needs to be folded into the CRC like any other completed byte
+        if self.bit_position % 8 != 0 {
+            let i = (self.bit_position / 8) as usize;
+            self.crc_byte(self.buffer[i]);
+        }
         self.bit_position = ((self.bit_position + 7) / 8) * 8;
         Ok(())
     }
@@ -72,6 +141,7 @@ impl BitwiseWriter for FileWriter {
         // write the byte directly into the buffer
         self.buffer[(self.bit_position / 8) as usize] = byte;
         self.bit_position += 8;
+        self.crc_byte(byte);
 
         Ok(())
     }
@@ -89,12 +159,16 @@ impl BitwiseWriter for FileWriter {
         self.update_buffer()?;
 
         // write the bit back into the buffer
+        let i = (self.bit_position / 8) as usize;
         if bit > 0 {
-            let i = (self.bit_position / 8) as usize;
             self.buffer[i] = bitwise::set_bit(self.buffer[i] as u32, self.bit_position % 8);
         }
 
         self.bit_position += 1;
+        // the byte at `i` is now fully written once bit_position crosses its boundary
+        if self.bit_position % 8 == 0 {
+            self.crc_byte(self.buffer[i]);
+        }
         Ok(())
     }
 
@@ -115,6 +189,7 @@ impl BitwiseWriter for FileWriter {
         // write each u64 field into the file
         self.write_u64(block.fbs.tree_bit_size)?;
         self.write_u64(block.fbs.data_bit_size)?;
+        self.write_u32(block.crc32)?;
         self.write_u64(block.file_byte_offset)?;
         self.write_u64(block.og_byte_size)?;
         Ok(())
@@ -127,12 +202,118 @@ impl BitwiseWriter for FileWriter {
         }
         Ok(())
     }
+
+    fn write_u32(&mut self, num: u32) -> io::Result<()> {
+        let buffer: [u8; 4] = unsafe { mem::transmute(num) };
+        for i in 0..4 {
+            self.write_byte(buffer[i])?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        // route through into_inner so the final flush only happens once: self is
+        // consumed here, so letting the implicit end-of-scope Drop run persist_buffer
+        // again (on top of the one below) would duplicate the final partial buffer
+        self.into_inner()?;
+        Ok(())
+    }
+
+    fn begin_crc(&mut self) {
+        self.crc.reset();
+        self.recording_crc = true;
+    }
+
+    fn end_crc(&mut self) -> u32 {
+        self.recording_crc = false;
+        self.crc.finalize()
+    }
 }
 
-impl Drop for FileWriter {
+impl<W: Write> Drop for BitWriter<W> {
     fn drop(&mut self) {
+        // finish() is the intended way to flush and observe the result; this is only a
+        // best-effort fallback for callers that dropped the writer without calling it
         if let Err(e) = self.persist_buffer() {
-            panic!("Fatal: failed to write the buffer to file when dropping: {}", e.to_string());
+            eprintln!("Warning: failed to flush the buffer to the writer when dropping BitWriter: {}", e);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // a writer that only accepts a handful of bytes per call, to exercise
+    // persist_buffer's loop over short writes
+    struct ShortWriter {
+        data: Vec<u8>,
+        max_chunk: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.max_chunk);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn persist_buffer_loops_over_short_writes() {
+        let mut writer = BitWriter::from_writer(ShortWriter { data: Vec::new(), max_chunk: 1 });
+        for byte in [0xAAu8, 0xBB, 0xCC] {
+            writer.write_byte(byte).unwrap();
+        }
+        let inner = writer.into_inner().unwrap();
+        assert_eq!(inner.data, vec![0xAA, 0xBB, 0xCC]);
+    }
+
+    // a writer sharing its buffer with the test so we can inspect it after finish()
+    // has consumed the BitWriter
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn finish_flushes_exactly_once() {
+        let sink = Rc::new(RefCell::new(Vec::new()));
+        let mut writer = BitWriter::from_writer(SharedWriter(sink.clone()));
+        writer.write_byte(0xAA).unwrap();
+        writer.write_byte(0xBB).unwrap();
+        writer.finish().unwrap();
+        // if Drop re-ran persist_buffer after finish()'s own flush, these bytes would
+        // be duplicated in the underlying sink
+        assert_eq!(*sink.borrow(), vec![0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn crc_round_trip_through_non_byte_aligned_writes() {
+        let mut writer = BitWriter::from_writer(Vec::new());
+        writer.begin_crc();
+        writer.write_byte(0xAB).unwrap();
+        writer.write_byte(0xCD).unwrap();
+        // a few extra bits so the recorded region doesn't end on a byte boundary,
+        // exercising the zero-padded final byte that align_to_byte must fold in
+        writer.write_bits(0b1011, 4).unwrap();
+        writer.align_to_byte().unwrap();
+        let crc = writer.end_crc();
+        let payload = writer.into_inner().unwrap();
+        assert_eq!(crc, crate::crc32::crc32(&payload));
+    }
+}